@@ -0,0 +1,266 @@
+//! Asynchronous Common Subset, built on top of reliable broadcast and binary
+//! agreement.
+//!
+//! One broadcast instance and one agreement instance run per proposer, both
+//! indexed by that proposer's node index. As soon as `RBC_j` delivers a
+//! value, `1` is input to `BA_j`. As soon as `N - f` agreement instances
+//! have decided `1`, `0` is input to every agreement instance that has not
+//! yet had an input. Once all `N` agreements have terminated, the output is
+//! the set of values delivered by those `RBC_j` for which `BA_j` decided `1`.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use ring::digest::Algorithm;
+use crossbeam::Scope;
+use crossbeam_channel::{Sender, Receiver, unbounded};
+use threshold_crypto::{PublicKeySet, SecretKeyShare};
+use messaging::{Messaging, SourcedMessage};
+use proto::Message;
+use broadcast::Broadcast;
+use agreement::Agreement;
+use common_coin::CommonCoin;
+
+pub struct Acs;
+
+impl Acs {
+    /// Runs the N broadcast and N agreement instances and delivers the
+    /// common subset on `output_tx`.
+    ///
+    /// `proposal` is this node's own value, if it has one, input to the
+    /// broadcast instance it proposes into (instance `node_index`).
+    /// `public_key_set`/`secret_key_share` are the threshold key material
+    /// used for every instance's common coin. Coin and decryption traffic
+    /// share the same BLS keys: the same share that signs a coin request can
+    /// also decrypt a share of a ciphertext, so one threshold key pair
+    /// serves both purposes. Messages addressed to the `Decryption` variant
+    /// are not this orchestrator's concern and are simply forwarded onward
+    /// via `decryption_tx`, for whoever collects decryption shares for the
+    /// delivered ciphertexts. `shutdown_rx` stops every common coin
+    /// instance's loop, letting its thread join, once a `()` has been sent
+    /// on it for each instance.
+    pub fn spawn<'a, T>(scope: &Scope<'a>,
+                         num_nodes: usize,
+                         node_index: usize,
+                         algorithm: &'static Algorithm,
+                         proposal: Option<T>,
+                         messaging: &Messaging<T>,
+                         public_key_set: PublicKeySet,
+                         secret_key_share: SecretKeyShare,
+                         decryption_tx: Sender<SourcedMessage<T>>,
+                         output_tx: Sender<HashMap<usize, T>>,
+                         shutdown_rx: Receiver<()>)
+    where T: Clone + Debug + Send + Sync + From<Vec<u8>> + Into<Vec<u8>> + 'a
+    {
+        let f = (num_nodes - 1) / 3;
+        let algo_tx = messaging.from_algo_tx().clone();
+
+        let (decided_tx, decided_rx) = unbounded();
+        let (values_tx, values_rx) = unbounded();
+        let mut input_txs = Vec::with_capacity(num_nodes);
+
+        for j in 1 ..= num_nodes {
+            // `Messaging` already routes Broadcast, Agreement and Decryption
+            // traffic straight to instance j's channel below; Coin messages
+            // are not yet tied to an instance and still arrive fanned out
+            // from every instance, so this demultiplexes by message kind
+            // into this instance's broadcast, agreement and coin
+            // sub-algorithms, and forwards decryption shares onward.
+            let raw_rx = messaging.to_algo_rxs()[j - 1].clone();
+            let (bcast_tx, bcast_rx) = unbounded();
+            let (agree_tx, agree_rx) = unbounded();
+            let (coin_in_tx, coin_in_rx) = unbounded();
+            let forward_decryption_tx = decryption_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let sourced = match raw_rx.recv() {
+                        Some(s) => s,
+                        None => break,
+                    };
+                    match sourced.message {
+                        Message::Broadcast(_, _) => { bcast_tx.send(sourced).unwrap(); },
+                        Message::Agreement(_, _) => { agree_tx.send(sourced).unwrap(); },
+                        Message::Coin(_) => { coin_in_tx.send(sourced).unwrap(); },
+                        Message::Decryption(_) => {
+                            forward_decryption_tx.send(sourced).unwrap();
+                        }
+                    }
+                }
+            });
+
+            let (rbc_out_tx, rbc_out_rx) = unbounded();
+            let broadcast = Broadcast::new(num_nodes, node_index, j as u32, algorithm);
+            let own_proposal = if j == node_index { proposal.clone() } else { None };
+            broadcast.spawn(scope, own_proposal, bcast_rx, algo_tx.clone(), rbc_out_tx);
+
+            let (coin_req_tx, coin_req_rx) = unbounded();
+            let (coin_res_tx, coin_res_rx) = unbounded();
+            let coin = CommonCoin::new(num_nodes, node_index, j as u32,
+                                        secret_key_share.clone(),
+                                        public_key_set.clone());
+            coin.spawn(scope, coin_req_rx, coin_in_rx, algo_tx.clone(), coin_res_tx,
+                       shutdown_rx.clone());
+
+            let (input_tx, input_rx) = unbounded();
+            let (decision_tx, decision_rx) = unbounded();
+            let agreement: Agreement<T> = Agreement::new(num_nodes, node_index, j as u32);
+            agreement.spawn(scope, input_rx, agree_rx, algo_tx.clone(),
+                             coin_req_tx, coin_res_rx, decision_tx);
+            input_txs.push(input_tx);
+
+            // As soon as RBC_j delivers, input 1 to BA_j and publish the
+            // value for collection once the common subset is known.
+            let values_tx = values_tx.clone();
+            let input_tx_for_rbc = input_txs[j - 1].clone();
+            scope.spawn(move || {
+                if let Some(value) = rbc_out_rx.recv() {
+                    values_tx.send((j, value)).unwrap();
+                    input_tx_for_rbc.send(true).unwrap();
+                }
+            });
+
+            // Forward BA_j's decision, tagged with its instance, to the
+            // single orchestrator loop below.
+            let decided_tx = decided_tx.clone();
+            scope.spawn(move || {
+                if let Some(decided) = decision_rx.recv() {
+                    decided_tx.send((j, decided)).unwrap();
+                }
+            });
+        }
+
+        scope.spawn(move || {
+            let mut decided_total = 0;
+            let mut ones = 0;
+            let mut decided_ones = HashMap::new();
+            let mut delivered = HashMap::new();
+            let mut zeroes_sent = false;
+
+            loop {
+                select_loop! {
+                    recv(decided_rx, (j, decided)) => {
+                        decided_total += 1;
+                        decided_ones.insert(j, decided);
+                        if decided {
+                            ones += 1;
+                        }
+                        if !zeroes_sent && zeroes_threshold_reached(ones, num_nodes, f) {
+                            zeroes_sent = true;
+                            for input_tx in input_txs.iter() {
+                                input_tx.send(false).unwrap();
+                            }
+                        }
+                    },
+                    recv(values_rx, (j, value)) => {
+                        delivered.insert(j, value);
+                    }
+                }
+
+                if is_complete(decided_total, num_nodes, &decided_ones, &delivered) {
+                    break;
+                }
+            }
+
+            output_tx.send(subset_from(decided_ones, &mut delivered)).unwrap();
+        });
+    }
+}
+
+/// Whether enough agreement instances have decided `1` that every instance
+/// still without an input should be given `0`: once `N - f` have, no
+/// instance still undecided can end up deciding `1` without the set
+/// overlapping an honest majority, so it is safe to push them towards `0`.
+fn zeroes_threshold_reached(ones: usize, num_nodes: usize, f: usize) -> bool {
+    ones >= num_nodes - f
+}
+
+/// Whether all `num_nodes` agreement instances have decided, and every
+/// instance that decided `1` has also had its broadcast value delivered, so
+/// the common subset can be assembled.
+fn is_complete<T>(decided_total: usize,
+                   num_nodes: usize,
+                   decided_ones: &HashMap<usize, bool>,
+                   delivered: &HashMap<usize, T>)
+                   -> bool
+{
+    decided_total == num_nodes
+        && decided_ones.iter()
+            .filter(|&(_, &d)| d)
+            .all(|(j, _)| delivered.contains_key(j))
+}
+
+/// Assembles the common subset: the values delivered by every `RBC_j` whose
+/// `BA_j` decided `1`.
+fn subset_from<T>(decided_ones: HashMap<usize, bool>,
+                   delivered: &mut HashMap<usize, T>)
+                   -> HashMap<usize, T>
+{
+    decided_ones.into_iter()
+        .filter(|&(_, decided)| decided)
+        .filter_map(|(j, _)| delivered.remove(&j).map(|v| (j, v)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zeroes_threshold_is_n_minus_f() {
+        // num_nodes = 4, f = 1: N - f = 3.
+        assert!(!zeroes_threshold_reached(2, 4, 1));
+        assert!(zeroes_threshold_reached(3, 4, 1));
+        assert!(zeroes_threshold_reached(4, 4, 1));
+    }
+
+    #[test]
+    fn not_complete_until_every_instance_has_decided() {
+        let mut decided_ones = HashMap::new();
+        decided_ones.insert(1, true);
+        decided_ones.insert(2, false);
+        let delivered: HashMap<usize, Vec<u8>> = [(1, vec![1u8])].iter().cloned().collect();
+
+        assert!(!is_complete(2, 4, &decided_ones, &delivered));
+    }
+
+    #[test]
+    fn not_complete_while_a_decided_one_is_missing_its_value() {
+        let mut decided_ones = HashMap::new();
+        decided_ones.insert(1, true);
+        decided_ones.insert(2, true);
+        decided_ones.insert(3, false);
+        decided_ones.insert(4, false);
+        // Instance 2 decided `1` but RBC_2 has not delivered yet.
+        let delivered: HashMap<usize, Vec<u8>> = [(1, vec![1u8])].iter().cloned().collect();
+
+        assert!(!is_complete(4, 4, &decided_ones, &delivered));
+    }
+
+    #[test]
+    fn complete_once_every_decided_one_has_its_value() {
+        let mut decided_ones = HashMap::new();
+        decided_ones.insert(1, true);
+        decided_ones.insert(2, false);
+        decided_ones.insert(3, false);
+        decided_ones.insert(4, false);
+        let delivered: HashMap<usize, Vec<u8>> = [(1, vec![1u8])].iter().cloned().collect();
+
+        assert!(is_complete(4, 4, &decided_ones, &delivered));
+    }
+
+    #[test]
+    fn subset_contains_only_delivered_values_for_decided_ones() {
+        let mut decided_ones = HashMap::new();
+        decided_ones.insert(1, true);
+        decided_ones.insert(2, true);
+        decided_ones.insert(3, false);
+        // Instance 2 decided `1` but never delivered a value, e.g. the node
+        // crashed mid-broadcast; its entry must be left out of the subset
+        // rather than panicking on the missing lookup.
+        let mut delivered: HashMap<usize, Vec<u8>> = HashMap::new();
+        delivered.insert(1, vec![42u8]);
+
+        let subset = subset_from(decided_ones, &mut delivered);
+
+        assert_eq!(subset.len(), 1);
+        assert_eq!(subset.get(&1), Some(&vec![42u8]));
+    }
+}
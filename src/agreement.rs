@@ -0,0 +1,398 @@
+//! Binary Byzantine agreement as described by Mostefaoui, Moumen and Raynal.
+//!
+//! Each instance agrees on a single bit given an initial estimate. Nodes
+//! proceed through rounds; within a round, nodes `BVal`-broadcast their
+//! estimate, gather `bin_values` once enough matching `BVal`s are seen, then
+//! `Aux`-broadcast a confirmed value and combine what they hear with a common
+//! coin to either decide or move to the next round with a new estimate.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use crossbeam::Scope;
+use crossbeam_channel::{Sender, Receiver};
+use messaging::{Target, TargetedMessage, SourcedMessage};
+use proto::{Message, AgreementMessage};
+
+/// Number of distinct senders seen so far for a given round and value.
+type VoteCount = HashMap<(u32, bool), HashSet<usize>>;
+
+/// A single instance of the binary agreement protocol, running on behalf of
+/// one node among `num_nodes`, where `num_nodes = 3 * f + 1`.
+///
+/// `T` is the batch type carried by the surrounding `Message<T>` envelope;
+/// agreement messages never depend on it, but the instance speaks the same
+/// wire type as the rest of the node so it can share `Messaging`'s channels.
+pub struct Agreement<T: Clone + Debug + Send + Sync> {
+    num_nodes: usize,
+    node_index: usize,
+    /// The proposer this instance is running binary agreement for; tags
+    /// every outgoing message so `Messaging` can route it to the matching
+    /// instance on the receiving end.
+    instance: u32,
+    /// Maximum number of faulty nodes tolerated.
+    f: usize,
+
+    /// The round currently being processed.
+    round: u32,
+    /// This node's current estimate, set once an initial value is input.
+    est: Option<bool>,
+    /// The decided value, once the instance has terminated.
+    decision: Option<bool>,
+
+    /// `BVal(r, v)` senders seen so far, keyed by `(r, v)`.
+    bval_senders: VoteCount,
+    /// `(r, v)` pairs this node has already echoed, to echo each at most once.
+    bval_echoed: HashSet<(u32, bool)>,
+    /// Values confirmed via `2f + 1` matching `BVal`s, keyed by round.
+    bin_values: HashMap<u32, HashSet<bool>>,
+    /// Whether this node has `Aux`-broadcast in a given round already.
+    aux_sent: HashSet<u32>,
+    /// `Aux(r, v)` senders seen so far, keyed by `(r, v)`.
+    aux_senders: VoteCount,
+    /// Rounds for which the common coin has already been requested, so that
+    /// further matching `Aux`s for an already-complete round don't request
+    /// it again.
+    coin_requested: HashSet<u32>,
+
+    _phantom: ::std::marker::PhantomData<T>,
+}
+
+impl<T: Clone + Debug + Send + Sync> Agreement<T> {
+    pub fn new(num_nodes: usize, node_index: usize, instance: u32) -> Self {
+        let f = (num_nodes - 1) / 3;
+        Agreement {
+            num_nodes,
+            node_index,
+            instance,
+            f,
+            round: 0,
+            est: None,
+            decision: None,
+            bval_senders: HashMap::new(),
+            bval_echoed: HashSet::new(),
+            bin_values: HashMap::new(),
+            aux_sent: HashSet::new(),
+            aux_senders: HashMap::new(),
+            coin_requested: HashSet::new(),
+            _phantom: ::std::marker::PhantomData,
+        }
+    }
+
+    pub fn is_decided(&self) -> bool {
+        self.decision.is_some()
+    }
+
+    /// Runs the instance to completion in the given thread scope. `input_rx`
+    /// carries this node's initial estimate; `in_rx`/`out_tx` carry the
+    /// already instance-demultiplexed agreement traffic; `coin_tx`/`coin_rx`
+    /// request and receive the common coin for a round; `decision_tx`
+    /// publishes the final decision once reached.
+    pub fn spawn<'a>(mut self,
+                      scope: &Scope<'a>,
+                      input_rx: Receiver<bool>,
+                      in_rx: Receiver<SourcedMessage<T>>,
+                      out_tx: Sender<TargetedMessage<T>>,
+                      coin_tx: Sender<u32>,
+                      coin_rx: Receiver<(u32, bool)>,
+                      decision_tx: Sender<bool>)
+    where T: 'a
+    {
+        scope.spawn(move || {
+            if let Some(est) = input_rx.recv() {
+                self.est = Some(est);
+                self.broadcast_bval(&out_tx, self.round, est);
+            }
+
+            loop {
+                select_loop! {
+                    recv(in_rx, sourced) => {
+                        self.handle_message(sourced, &out_tx, &coin_tx);
+                    },
+                    recv(coin_rx, (round, coin)) => {
+                        self.handle_coin(round, coin, &out_tx, &decision_tx);
+                    }
+                }
+                if self.decision.is_some() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn handle_message(&mut self,
+                       sourced: SourcedMessage<T>,
+                       out_tx: &Sender<TargetedMessage<T>>,
+                       coin_tx: &Sender<u32>)
+    {
+        let SourcedMessage { source, message } = sourced;
+        let message = match message {
+            // `Messaging` has already routed this to our instance; the
+            // `instance` tag itself is only needed on the wire.
+            Message::Agreement(_, a) => a,
+            // Not an agreement message; `Coin` traffic is not yet tied to an
+            // instance and is still fanned out to every instance running
+            // locally, so this is routine and ignored.
+            _ => return,
+        };
+        match message {
+            AgreementMessage::BVal(round, value) => {
+                self.receive_bval(source, round, value, out_tx, coin_tx);
+            },
+            AgreementMessage::Aux(round, value) => {
+                self.receive_aux(source, round, value, coin_tx);
+            }
+        }
+    }
+
+    fn receive_bval(&mut self,
+                     source: usize,
+                     round: u32,
+                     value: bool,
+                     out_tx: &Sender<TargetedMessage<T>>,
+                     coin_tx: &Sender<u32>)
+    {
+        let senders = self.bval_senders.entry((round, value))
+            .or_insert_with(HashSet::new);
+        senders.insert(source);
+        let count = senders.len();
+
+        if count == self.f + 1 && !self.bval_echoed.contains(&(round, value)) {
+            self.bval_echoed.insert((round, value));
+            self.broadcast_bval(out_tx, round, value);
+        }
+
+        if count == 2 * self.f + 1 {
+            let is_new = self.bin_values.entry(round)
+                .or_insert_with(HashSet::new)
+                .insert(value);
+            if is_new && round == self.round {
+                self.broadcast_aux(out_tx, coin_tx, round, value);
+            }
+        }
+    }
+
+    fn receive_aux(&mut self,
+                    source: usize,
+                    round: u32,
+                    value: bool,
+                    coin_tx: &Sender<u32>)
+    {
+        self.aux_senders.entry((round, value))
+            .or_insert_with(HashSet::new)
+            .insert(source);
+        self.try_complete_round(round, coin_tx);
+    }
+
+    /// Broadcasts `Aux(round, value)` once per round, then checks whether the
+    /// round can already be completed with the `Aux`s seen so far.
+    fn broadcast_aux(&mut self,
+                      out_tx: &Sender<TargetedMessage<T>>,
+                      coin_tx: &Sender<u32>,
+                      round: u32,
+                      value: bool)
+    {
+        if self.aux_sent.insert(round) {
+            self.send(out_tx, AgreementMessage::Aux(round, value));
+        }
+        self.try_complete_round(round, coin_tx);
+    }
+
+    /// Once `N - f` `Aux`s are in for `round`, all pointing into `bin_values`,
+    /// requests the common coin for that round.
+    fn try_complete_round(&mut self, round: u32, coin_tx: &Sender<u32>) {
+        if round != self.round || !self.aux_sent.contains(&round) {
+            return;
+        }
+        let bin_values = match self.bin_values.get(&round) {
+            Some(values) => values.clone(),
+            None => return,
+        };
+
+        let mut vals = HashSet::new();
+        let mut senders = HashSet::new();
+        for &value in &bin_values {
+            if let Some(s) = self.aux_senders.get(&(round, value)) {
+                senders.extend(s.iter().cloned());
+                vals.insert(value);
+            }
+        }
+        if senders.len() >= self.num_nodes - self.f && self.coin_requested.insert(round) {
+            coin_tx.send(round).unwrap();
+        }
+    }
+
+    fn handle_coin(&mut self,
+                    round: u32,
+                    coin: bool,
+                    out_tx: &Sender<TargetedMessage<T>>,
+                    decision_tx: &Sender<bool>)
+    {
+        if round != self.round {
+            return;
+        }
+        let vals = self.bin_values.get(&round).cloned().unwrap_or_default();
+
+        let next_est = if vals.len() == 1 {
+            let v = *vals.iter().next().unwrap();
+            if v == coin {
+                self.decision = Some(v);
+                decision_tx.send(v).unwrap();
+                return;
+            }
+            v
+        } else {
+            coin
+        };
+
+        self.round += 1;
+        self.est = Some(next_est);
+        self.broadcast_bval(out_tx, self.round, next_est);
+
+        // A value may already have reached 2f + 1 BVals for the new round
+        // before we got here, e.g. because faster nodes moved on to it while
+        // we were still finishing the old one; `receive_bval` only
+        // `Aux`-broadcasts such a value when it is freshly confirmed, so
+        // catch up on it here instead of waiting for a `BVal` that has
+        // already been seen and will never arrive again.
+        let confirmed = self.bin_values.get(&self.round).and_then(|v| v.iter().next().cloned());
+        if let Some(value) = confirmed {
+            self.broadcast_aux(out_tx, coin_tx, self.round, value);
+        }
+    }
+
+    fn broadcast_bval(&mut self,
+                       out_tx: &Sender<TargetedMessage<T>>,
+                       round: u32,
+                       value: bool)
+    {
+        self.bval_echoed.insert((round, value));
+        self.send(out_tx, AgreementMessage::BVal(round, value));
+    }
+
+    fn send(&self,
+            out_tx: &Sender<TargetedMessage<T>>,
+            message: AgreementMessage)
+    {
+        let message = Message::Agreement(self.instance, message);
+        if let Some(t) = TargetedMessage::new(Target::All, message) {
+            out_tx.send(t).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    fn new_agreement() -> Agreement<Vec<u8>> {
+        // num_nodes = 4, so f = 1: 2 BVals echo, 3 BVals confirm a value.
+        Agreement::new(4, 1, 1)
+    }
+
+    fn sent_auxes(out_rx: &Receiver<TargetedMessage<Vec<u8>>>) -> Vec<(u32, bool)> {
+        let mut auxes = Vec::new();
+        while let Some(t) = out_rx.try_recv() {
+            if let Message::Agreement(_, AgreementMessage::Aux(round, value)) = t.message {
+                auxes.push((round, value));
+            }
+        }
+        auxes
+    }
+
+    #[test]
+    fn receive_bval_echoes_once_at_f_plus_1_and_confirms_at_2f_plus_1() {
+        let mut agreement = new_agreement();
+        let (out_tx, out_rx) = unbounded();
+        let (coin_tx, _coin_rx) = unbounded();
+
+        agreement.receive_bval(2, 0, true, &out_tx, &coin_tx);
+        assert!(!agreement.bval_echoed.contains(&(0, true)));
+
+        agreement.receive_bval(3, 0, true, &out_tx, &coin_tx);
+        assert!(agreement.bval_echoed.contains(&(0, true)));
+        assert!(!agreement.bin_values.contains_key(&0));
+
+        agreement.receive_bval(4, 0, true, &out_tx, &coin_tx);
+        assert!(agreement.bin_values[&0].contains(&true));
+        assert!(agreement.aux_sent.contains(&0));
+        assert_eq!(sent_auxes(&out_rx), vec![(0, true)]);
+    }
+
+    #[test]
+    fn receive_bval_confirming_a_future_round_does_not_aux_early() {
+        let mut agreement = new_agreement();
+        let (out_tx, out_rx) = unbounded();
+        let (coin_tx, _coin_rx) = unbounded();
+
+        // 2f + 1 BVal(1, true) while still in round 0: confirmed into
+        // bin_values, but round 1 hasn't started yet, so no Aux goes out.
+        for source in 2 ..= 4 {
+            agreement.receive_bval(source, 1, true, &out_tx, &coin_tx);
+        }
+        assert!(agreement.bin_values[&1].contains(&true));
+        assert!(!agreement.aux_sent.contains(&1));
+        assert!(sent_auxes(&out_rx).is_empty());
+    }
+
+    #[test]
+    fn handle_coin_resends_aux_for_a_round_confirmed_ahead_of_time() {
+        let mut agreement = new_agreement();
+        let (out_tx, out_rx) = unbounded();
+        let (decision_tx, _decision_rx) = unbounded();
+
+        // Round 1 was already confirmed to `true` while this node was still
+        // in round 0, e.g. by faster peers (see the test above); that must
+        // not be lost once the node actually advances into round 1.
+        agreement.bin_values.insert(1, [true].iter().cloned().collect());
+        agreement.est = Some(false);
+
+        agreement.handle_coin(0, false, &out_tx, &decision_tx);
+
+        assert_eq!(agreement.round, 1);
+        assert!(agreement.aux_sent.contains(&1));
+        assert_eq!(sent_auxes(&out_rx), vec![(1, true)]);
+    }
+
+    #[test]
+    fn try_complete_round_requests_the_coin_only_once_per_round() {
+        let mut agreement = new_agreement();
+        let (coin_tx, coin_rx) = unbounded();
+
+        agreement.aux_sent.insert(0);
+        agreement.bin_values.insert(0, [true].iter().cloned().collect());
+
+        agreement.receive_aux(2, 0, true, &coin_tx);
+        assert!(coin_rx.try_recv().is_none());
+
+        agreement.receive_aux(3, 0, true, &coin_tx);
+        assert!(coin_rx.try_recv().is_none());
+
+        // num_nodes = 4, f = 1: N - f = 3 matching Auxes are needed.
+        agreement.receive_aux(4, 0, true, &coin_tx);
+        assert_eq!(coin_rx.try_recv(), Some(0));
+
+        // Once the round's threshold is already met, a further matching Aux
+        // (e.g. a retransmission) must not request the coin again.
+        agreement.receive_aux(4, 0, true, &coin_tx);
+        assert!(coin_rx.try_recv().is_none());
+
+        // A later Aux for a round that hasn't been Aux-broadcast locally yet
+        // must not request a coin.
+        agreement.receive_aux(4, 1, true, &coin_tx);
+        assert!(coin_rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn handle_coin_decides_when_exactly_one_value_matches_the_coin() {
+        let mut agreement = new_agreement();
+        let (out_tx, _out_rx) = unbounded();
+        let (decision_tx, decision_rx) = unbounded();
+
+        agreement.bin_values.insert(0, [true].iter().cloned().collect());
+        agreement.handle_coin(0, true, &out_tx, &decision_tx);
+
+        assert_eq!(agreement.decision, Some(true));
+        assert_eq!(decision_rx.try_recv(), Some(true));
+        assert_eq!(agreement.round, 0);
+    }
+}
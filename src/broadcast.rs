@@ -0,0 +1,269 @@
+//! Reliable broadcast (Cachin-Tessaro), erasure-coded and Merkle-authenticated.
+//!
+//! The proposer splits its value into `N` Reed-Solomon shards, any `f + 1` of
+//! which suffice to reconstruct it, and commits to them with a Merkle tree.
+//! It unicasts each node its shard together with a proof against the root
+//! hash (`Value`). Nodes echo what they received to everyone (`Echo`); once
+//! `N - f` matching, valid echoes for a root are in, a node can reconstruct
+//! the value and broadcasts `Ready(root_hash)`. Echoing `Ready` on either
+//! `f + 1` `Ready`s (to help it spread) or `N - f` of them (to decide)
+//! guarantees that all honest nodes eventually deliver the same value.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use ring::digest::Algorithm;
+use crossbeam::Scope;
+use crossbeam_channel::{Sender, Receiver};
+use reed_solomon_erasure::ReedSolomon;
+use merkle::MerkleTree;
+use merkle::proof::Proof;
+use messaging::{Target, TargetedMessage, SourcedMessage};
+use proto::{Message, BroadcastMessage, ErasureCoding};
+
+pub struct Broadcast<T: Clone + Debug + Send + Sync> {
+    num_nodes: usize,
+    node_index: usize,
+    /// The proposer this instance is running reliable broadcast for; tags
+    /// every outgoing message so `Messaging` can route it to the matching
+    /// instance on the receiving end.
+    instance: u32,
+    f: usize,
+    algorithm: &'static Algorithm,
+
+    /// Shards echoed by each sender, keyed by the root hash they commit to.
+    echos: HashMap<Vec<u8>, HashMap<usize, Proof<T>>>,
+    /// Whether this node has echoed a given root hash already.
+    echo_sent: HashSet<Vec<u8>>,
+    /// Senders of `Ready(root_hash)`, keyed by root hash.
+    readys: HashMap<Vec<u8>, HashSet<usize>>,
+    /// Whether this node has sent `Ready` for a given root hash already.
+    ready_sent: HashSet<Vec<u8>>,
+    /// The value, once reconstructed and delivered.
+    output: Option<T>,
+}
+
+impl<T> Broadcast<T>
+    where T: Clone + Debug + Send + Sync + Into<Vec<u8>> + From<Vec<u8>>
+{
+    pub fn new(num_nodes: usize,
+               node_index: usize,
+               instance: u32,
+               algorithm: &'static Algorithm)
+               -> Self
+    {
+        Broadcast {
+            num_nodes,
+            node_index,
+            instance,
+            f: (num_nodes - 1) / 3,
+            algorithm,
+            echos: HashMap::new(),
+            echo_sent: HashSet::new(),
+            readys: HashMap::new(),
+            ready_sent: HashSet::new(),
+            output: None,
+        }
+    }
+
+    /// Runs the instance in the given thread scope. If `proposal` is `Some`,
+    /// this node is the proposer for the instance and starts by encoding and
+    /// distributing it; `in_rx`/`out_tx` carry the already
+    /// instance-demultiplexed broadcast traffic; `output_tx` publishes the
+    /// delivered value, once reconstructed.
+    pub fn spawn<'a>(mut self,
+                      scope: &Scope<'a>,
+                      proposal: Option<T>,
+                      in_rx: Receiver<SourcedMessage<T>>,
+                      out_tx: Sender<TargetedMessage<T>>,
+                      output_tx: Sender<T>)
+    where T: 'a
+    {
+        scope.spawn(move || {
+            if let Some(value) = proposal {
+                self.send_shards(value, &out_tx);
+            }
+
+            loop {
+                let sourced = match in_rx.recv() {
+                    Some(s) => s,
+                    None => break,
+                };
+                self.handle_message(sourced, &out_tx, &output_tx);
+                if self.output.is_some() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Erasure-codes `value` into `num_nodes` shards, any `f + 1` of which
+    /// reconstruct it, commits to them with a Merkle tree, and unicasts each
+    /// node its shard with the accompanying proof.
+    fn send_shards(&mut self, value: T, out_tx: &Sender<TargetedMessage<T>>) {
+        let data_shard_count = self.f + 1;
+        let parity_shard_count = self.num_nodes - data_shard_count;
+        let rs = match ReedSolomon::new(data_shard_count, parity_shard_count) {
+            Ok(rs) => rs,
+            Err(_) => return,
+        };
+
+        let bytes: Vec<u8> = value.into();
+        let shard_len = (bytes.len() + data_shard_count - 1) / data_shard_count;
+        let mut shards: Vec<Vec<u8>> = bytes
+            .chunks(shard_len)
+            .map(|c| {
+                let mut s = c.to_vec();
+                s.resize(shard_len, 0);
+                s
+            })
+            .collect();
+        shards.resize(self.num_nodes, vec![0u8; shard_len]);
+        if rs.encode(&mut shards).is_err() {
+            return;
+        }
+
+        let tree = MerkleTree::from_vec(self.algorithm, shards);
+        let erasure_coding = self.erasure_coding();
+        for i in 0 .. self.num_nodes {
+            let proof = match tree.gen_proof(i) {
+                Some(proof) => proof,
+                None => continue,
+            };
+            // Remote node indices start from 1.
+            let target = Target::Node(i + 1);
+            let message = BroadcastMessage::Value(erasure_coding, proof);
+            if let Some(t) = TargetedMessage::new(
+                target, Message::Broadcast(self.instance, message))
+            {
+                out_tx.send(t).unwrap();
+            }
+        }
+    }
+
+    /// The Reed-Solomon parameters this instance shards its value with,
+    /// advertised on every outgoing proof so a receiver with a differently
+    /// configured `f`/`num_nodes` rejects it instead of reconstructing
+    /// garbage from mismatched shards.
+    fn erasure_coding(&self) -> ErasureCoding {
+        ErasureCoding::expected(self.num_nodes)
+    }
+
+    fn handle_message(&mut self,
+                       sourced: SourcedMessage<T>,
+                       out_tx: &Sender<TargetedMessage<T>>,
+                       output_tx: &Sender<T>)
+    {
+        let SourcedMessage { source, message } = sourced;
+        let message = match message {
+            // `Messaging` has already routed this to our instance; the
+            // `instance` tag itself is only needed on the wire.
+            Message::Broadcast(_, b) => b,
+            // Not a broadcast message; `Coin` traffic is not yet tied to an
+            // instance and is still fanned out to every instance running
+            // locally, so this is routine and ignored.
+            _ => return,
+        };
+        match message {
+            BroadcastMessage::Value(_, proof) => self.receive_value(proof, out_tx),
+            BroadcastMessage::Echo(_, proof) => self.receive_echo(source, proof, out_tx),
+            BroadcastMessage::Ready(root_hash) => {
+                self.receive_ready(source, root_hash, out_tx, output_tx);
+            }
+        }
+    }
+
+    /// A `Value` from the proposer: echo it on to every node, once.
+    fn receive_value(&mut self, proof: Proof<T>, out_tx: &Sender<TargetedMessage<T>>) {
+        if !proof.validate(self.algorithm) {
+            return;
+        }
+        let root_hash = proof.root_hash.clone();
+        if self.echo_sent.insert(root_hash) {
+            let message = BroadcastMessage::Echo(self.erasure_coding(), proof);
+            if let Some(t) = TargetedMessage::new(
+                Target::All, Message::Broadcast(self.instance, message))
+            {
+                out_tx.send(t).unwrap();
+            }
+        }
+    }
+
+    fn receive_echo(&mut self,
+                    source: usize,
+                    proof: Proof<T>,
+                    out_tx: &Sender<TargetedMessage<T>>)
+    {
+        if !proof.validate(self.algorithm) {
+            return;
+        }
+        let root_hash = proof.root_hash.clone();
+        self.echos.entry(root_hash.clone())
+            .or_insert_with(HashMap::new)
+            .insert(source, proof);
+
+        let count = self.echos[&root_hash].len();
+        if count >= self.num_nodes - self.f {
+            self.send_ready(root_hash, out_tx);
+        }
+    }
+
+    fn receive_ready(&mut self,
+                     source: usize,
+                     root_hash: Vec<u8>,
+                     out_tx: &Sender<TargetedMessage<T>>,
+                     output_tx: &Sender<T>)
+    {
+        self.readys.entry(root_hash.clone())
+            .or_insert_with(HashSet::new)
+            .insert(source);
+        let count = self.readys[&root_hash].len();
+
+        if count == self.f + 1 {
+            // Amplify: even without N - f matching echoes of our own,
+            // f + 1 Readys are enough to know an honest node has seen them.
+            self.send_ready(root_hash.clone(), out_tx);
+        }
+
+        if count >= self.num_nodes - self.f {
+            if let Some(value) = self.reconstruct(&root_hash) {
+                self.output = Some(value.clone());
+                output_tx.send(value).unwrap();
+            }
+        }
+    }
+
+    fn send_ready(&mut self, root_hash: Vec<u8>, out_tx: &Sender<TargetedMessage<T>>) {
+        if self.ready_sent.insert(root_hash.clone()) {
+            let message = BroadcastMessage::Ready(root_hash);
+            if let Some(t) = TargetedMessage::new(
+                Target::All, Message::Broadcast(self.instance, message))
+            {
+                out_tx.send(t).unwrap();
+            }
+        }
+    }
+
+    /// Reconstructs the value from any `f + 1` of the echoed shards for
+    /// `root_hash`.
+    fn reconstruct(&self, root_hash: &[u8]) -> Option<T> {
+        let echos = self.echos.get(root_hash)?;
+        if echos.len() < self.f + 1 {
+            return None;
+        }
+
+        let data_shard_count = self.f + 1;
+        let parity_shard_count = self.num_nodes - data_shard_count;
+        let rs = ReedSolomon::new(data_shard_count, parity_shard_count).ok()?;
+
+        let mut shards: Vec<Option<Vec<u8>>> = vec![None; self.num_nodes];
+        for (&sender, proof) in echos.iter() {
+            shards[sender - 1] = Some(proof.value.clone().into());
+        }
+        rs.reconstruct(&mut shards).ok()?;
+
+        let mut bytes = Vec::new();
+        for shard in shards.into_iter().take(data_shard_count) {
+            bytes.extend(shard?);
+        }
+        Some(T::from(bytes))
+    }
+}
@@ -0,0 +1,158 @@
+//! A common coin built from a threshold BLS signature.
+//!
+//! Every node holds a share of a threshold secret key. To derive the coin for
+//! a given round/nonce `id`, a node signs the bytes of `(instance, id)` with
+//! its share and broadcasts `CoinMessage { instance, id, share }`. Once
+//! `f + 1` valid shares for the same `(instance, id)` have arrived, they
+//! Lagrange-combine into the unique threshold signature over that payload;
+//! its bit is the coin. Tagging the payload with `instance` keeps the N
+//! agreement instances' coins independent: `Coin` messages are not yet
+//! routed by instance (see `Message::instance`) and so arrive fanned out to
+//! every instance a node runs locally, and without `instance` in the signed
+//! payload, two instances at the same round number would derive and could
+//! combine into the same coin. Because the combined signature is unique
+//! regardless of which `f + 1` shares produced it, every honest node that
+//! completes the combination for a given instance arrives at the same bit.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use byteorder::{BigEndian, WriteBytesExt};
+use crossbeam::Scope;
+use crossbeam_channel::{Sender, Receiver};
+use threshold_crypto::{SecretKeyShare, PublicKeySet, SignatureShare};
+use messaging::{Target, TargetedMessage, SourcedMessage};
+use proto::{Message, CoinMessage};
+
+/// The payload signed for the common coin of round `id` of agreement
+/// instance `instance`: the two numbers concatenated big-endian, so that no
+/// two distinct `(instance, id)` pairs ever sign the same bytes.
+fn coin_payload(instance: u32, id: u32) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8);
+    payload.write_u32::<BigEndian>(instance).unwrap();
+    payload.write_u32::<BigEndian>(id).unwrap();
+    payload
+}
+
+pub struct CommonCoin<T: Clone + Debug + Send + Sync> {
+    node_index: usize,
+    /// The agreement instance this coin is serving; tags every outgoing
+    /// share and the signed payload itself, so that shares for a different
+    /// instance's coin (which still arrive here, fanned out) are ignored
+    /// rather than mistakenly combined into this instance's coin.
+    instance: u32,
+    f: usize,
+    secret_key_share: SecretKeyShare,
+    public_key_set: PublicKeySet,
+    /// Shares received so far, keyed by the id they were requested for.
+    shares: HashMap<u32, HashMap<usize, SignatureShare>>,
+    /// Ids for which the coin has already been derived and delivered.
+    done: HashMap<u32, bool>,
+    _phantom: ::std::marker::PhantomData<T>,
+}
+
+impl<T: Clone + Debug + Send + Sync> CommonCoin<T> {
+    pub fn new(num_nodes: usize,
+               node_index: usize,
+               instance: u32,
+               secret_key_share: SecretKeyShare,
+               public_key_set: PublicKeySet)
+               -> Self
+    {
+        CommonCoin {
+            node_index,
+            instance,
+            f: (num_nodes - 1) / 3,
+            secret_key_share,
+            public_key_set,
+            shares: HashMap::new(),
+            done: HashMap::new(),
+            _phantom: ::std::marker::PhantomData,
+        }
+    }
+
+    /// Runs the coin instance in the given thread scope. `request_rx` carries
+    /// ids the local node wants a coin for; `in_rx`/`out_tx` carry the
+    /// already instance-demultiplexed coin traffic; `coin_tx` delivers the
+    /// derived bit for each id, once. `shutdown_rx` stops the loop, letting
+    /// the thread join, once a `()` has been sent on it.
+    pub fn spawn<'a>(mut self,
+                      scope: &Scope<'a>,
+                      request_rx: Receiver<u32>,
+                      in_rx: Receiver<SourcedMessage<T>>,
+                      out_tx: Sender<TargetedMessage<T>>,
+                      coin_tx: Sender<(u32, bool)>,
+                      shutdown_rx: Receiver<()>)
+    where T: 'a
+    {
+        scope.spawn(move || {
+            loop {
+                select_loop! {
+                    recv(request_rx, id) => {
+                        let share = self.secret_key_share.sign(&coin_payload(self.instance, id));
+                        self.receive_share(self.node_index, id,
+                                            share.clone(), &coin_tx);
+                        let message = CoinMessage {
+                            instance: self.instance,
+                            id,
+                            share: share.to_bytes(),
+                        };
+                        if let Some(t) = TargetedMessage::new(
+                            Target::All, Message::Coin(message))
+                        {
+                            out_tx.send(t).unwrap();
+                        }
+                    },
+                    recv(in_rx, sourced) => {
+                        let SourcedMessage { source, message } = sourced;
+                        if let Message::Coin(CoinMessage { instance, id, share }) = message {
+                            // Still fanned out to every instance running
+                            // locally; only our own instance's shares count.
+                            if instance != self.instance {
+                                continue;
+                            }
+                            if let Ok(share) = SignatureShare::from_bytes(&share) {
+                                self.receive_share(source, id, share, &coin_tx);
+                            }
+                        }
+                    },
+                    recv(shutdown_rx, _) => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    fn receive_share(&mut self,
+                      source: usize,
+                      id: u32,
+                      share: SignatureShare,
+                      coin_tx: &Sender<(u32, bool)>)
+    {
+        if *self.done.get(&id).unwrap_or(&false) {
+            return;
+        }
+
+        self.shares.entry(id).or_insert_with(HashMap::new).insert(source, share);
+        let shares = &self.shares[&id];
+        if shares.len() < self.f + 1 {
+            return;
+        }
+
+        let combined = self.public_key_set.combine_signatures(
+            shares.iter().map(|(&i, s)| (i, s)));
+        let sig = match combined {
+            Ok(sig) => sig,
+            // Combination failed, likely due to a bogus share among the
+            // f + 1 received; wait for more shares to outvote it.
+            Err(_) => return,
+        };
+
+        if !self.public_key_set.public_key().verify(&sig, &coin_payload(self.instance, id)) {
+            return;
+        }
+
+        self.done.insert(id, true);
+        let bit = sig.to_bytes()[0] & 1 == 1;
+        coin_tx.send((id, bit)).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+//! Per-connection comms tasks bridging a TCP stream to `Messaging`.
+use std::fmt::Debug;
+use std::net::TcpStream;
+use crossbeam::Scope;
+use crossbeam_channel::{Sender, Receiver};
+use ring::digest::Algorithm;
+use proto_io::ProtoIo;
+use messaging::SourcedMessage;
+use proto::Message;
+
+/// Spawns the two comms tasks, send and receive, for a single remote node
+/// `node_index` (1-based) over `stream`. `algorithm` and `num_nodes` are this
+/// node's own configuration, passed on to `ProtoIo` to validate incoming
+/// broadcast proofs against.
+pub fn spawn<'a, T>(scope: &Scope<'a>,
+                     node_index: usize,
+                     stream: TcpStream,
+                     algorithm: &'static Algorithm,
+                     num_nodes: usize,
+                     to_comms_rx: Receiver<Message<T>>,
+                     from_comms_tx: Sender<SourcedMessage<T>>)
+where T: Clone + Debug + Send + Sync + From<Vec<u8>> + Into<Vec<u8>> + 'a
+{
+    let send_stream = stream.try_clone().expect("could not clone TCP stream");
+
+    scope.spawn(move || {
+        let mut io = ProtoIo::new(send_stream, algorithm, num_nodes);
+        loop {
+            match to_comms_rx.recv() {
+                Some(message) => {
+                    if io.send_message(message).is_err() {
+                        break;
+                    }
+                },
+                None => break,
+            }
+        }
+    });
+
+    scope.spawn(move || {
+        let mut io = ProtoIo::new(stream, algorithm, num_nodes);
+        loop {
+            match io.recv_message::<T>() {
+                Ok(Some(message)) => {
+                    let sourced = SourcedMessage { source: node_index, message };
+                    if from_comms_tx.send(sourced).is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => continue,
+                Err(_) => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,79 @@
+//! Establishing the full mesh of TCP connections between consensus nodes.
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Connects to every node in `remote_addresses`, retrying each dial until it
+/// succeeds, while concurrently accepting the inbound connections the other
+/// nodes make to us on `bind_address`. Returns one stream per remote address,
+/// in the given order, regardless of which side initiated the connection.
+///
+/// Each connection is attributed to a remote address by having the dialing
+/// side send that address's index (its position in the full node list) as
+/// the first four bytes on the wire, so the accepting side can sort inbound
+/// streams into the right slot.
+pub fn make_connections(bind_address: &SocketAddr,
+                         remote_addresses: &[SocketAddr])
+                         -> Vec<TcpStream>
+{
+    let mut all_addresses = remote_addresses.to_vec();
+    all_addresses.push(*bind_address);
+    all_addresses.sort();
+    let node_index = |addr: &SocketAddr| {
+        all_addresses.binary_search(addr).unwrap() as u32 + 1
+    };
+    let own_index = node_index(bind_address);
+
+    let listener = TcpListener::bind(bind_address)
+        .expect("could not bind to the given address");
+
+    let accept_count = remote_addresses.iter()
+        .filter(|addr| *addr < bind_address)
+        .count();
+
+    let acceptor = thread::spawn(move || {
+        let mut streams = Vec::with_capacity(accept_count);
+        for stream in listener.incoming().take(accept_count) {
+            if let Ok(mut stream) = stream {
+                if let Ok(index) = stream.read_u32::<BigEndian>() {
+                    streams.push((index, stream));
+                }
+            }
+        }
+        streams
+    });
+
+    let mut dialed = Vec::new();
+    for addr in remote_addresses.iter().filter(|addr| *addr > bind_address) {
+        loop {
+            match TcpStream::connect(addr) {
+                Ok(mut stream) => {
+                    if stream.write_u32::<BigEndian>(own_index).is_ok() {
+                        dialed.push((*addr, stream));
+                        break;
+                    }
+                },
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    let mut accepted = acceptor.join().unwrap_or_default();
+
+    // Streams we dialed are already attributed to their remote address; for
+    // accepted streams, match the index the dialing side handshook with
+    // against that address's own index in the full node list, since accept
+    // order has no guaranteed relationship to `remote_addresses` order.
+    remote_addresses.iter().map(|addr| {
+        if let Some(pos) = dialed.iter().position(|&(a, _)| a == *addr) {
+            dialed.remove(pos).1
+        } else {
+            let index = node_index(addr);
+            let pos = accepted.iter().position(|&(i, _)| i == index)
+                .expect("no handshake received for this address");
+            accepted.remove(pos).1
+        }
+    }).collect()
+}
@@ -9,6 +9,7 @@
 //!
 //! ```rust
 //! extern crate hbbft;
+//! extern crate threshold_crypto;
 //!
 //! use hbbft::node::Node;
 //! use std::net::SocketAddr;
@@ -22,10 +23,20 @@
 //!                                 "192.168.1.5:10005".parse().unwrap()];
 //!     let value: &'static str = "Proposed value";
 //!
-//!     let result = Node::new(bind_address, remote_addresses, Some(value))
+//!     // `public_key_set` and `secret_key_share` are generated once by a
+//!     // trusted dealer and distributed to each host out of band; here
+//!     // `key_material` stands in for however that distribution happened.
+//!     let (public_key_set, secret_key_share) = key_material();
+//!
+//!     let result = Node::new(bind_address, remote_addresses, Some(value),
+//!                             public_key_set, secret_key_share)
 //!         .run();
 //!     println!("Consensus result {:?}", result);
 //! }
+//! # fn key_material() -> (threshold_crypto::PublicKeySet,
+//! #                       threshold_crypto::SecretKeyShare) {
+//! #     unimplemented!()
+//! # }
 //! ```
 //!
 //! Similar code shall then run on hosts 192.168.1.2, 192.168.1.3, 192.168.1.4
@@ -44,6 +55,8 @@ extern crate crossbeam;
 #[macro_use]
 extern crate crossbeam_channel;
 extern crate reed_solomon_erasure;
+extern crate threshold_crypto;
+extern crate byteorder;
 
 mod connection;
 mod messaging;
@@ -52,5 +65,7 @@ mod proto_io;
 mod commst;
 mod broadcast;
 mod agreement;
+mod common_coin;
+mod acs;
 
 pub mod node;
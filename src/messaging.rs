@@ -5,12 +5,16 @@ use crossbeam::Scope;
 use crossbeam_channel::{unbounded, Sender, Receiver};
 use proto::Message;
 
-/// Message destination can be either of the two:
+/// Message destination on the network side, used for messages addressed to
+/// comms tasks (i.e. other nodes).
 ///
-/// 1) `All`: all nodes, if sent to socket tasks, or all local algorithm
-/// instances, if received from socket tasks.
+/// 1) `All`: all nodes.
 ///
-/// 2) `Node(i)`: node i or local algorithm instances with the node index i.
+/// 2) `Node(i)`: node `i`.
+///
+/// Messages addressed to local algorithm instances are not routed via
+/// `Target`; they are demultiplexed by instance index instead, see
+/// `Message::instance`.
 #[derive(Clone, Debug)]
 pub enum Target {
     All,
@@ -53,6 +57,10 @@ pub struct SourcedMessage<T: Clone + Debug + Send + Sync> {
 pub struct Messaging<T: Clone + Debug + Send + Sync> {
     /// The total number of consensus nodes for indexing purposes.
     num_nodes: usize,
+    /// This node's own 1-based index among the full, sorted set of node
+    /// addresses, used to place the gap `to_comms_txs`/`to_comms_rxs` leave
+    /// for the (non-existent) comms channel to oneself; see `comms_slot`.
+    node_index: usize,
 
     /// Transmit sides of message channels to comms threads.
     to_comms_txs: Vec<Sender<Message<T>>>,
@@ -75,8 +83,9 @@ pub struct Messaging<T: Clone + Debug + Send + Sync> {
 
 impl<T: Clone + Debug + Send + Sync> Messaging<T> {
     /// Initialises all the required TX and RX handles for the case on a total
-    /// number `num_nodes` of consensus nodes.
-    pub fn new(num_nodes: usize) -> Self
+    /// number `num_nodes` of consensus nodes. `node_index` is this node's own
+    /// 1-based index among the full, sorted set of node addresses.
+    pub fn new(num_nodes: usize, node_index: usize) -> Self
     {
         let to_comms: Vec<(Sender<Message<T>>, Receiver<Message<T>>)>
             = (0 .. num_nodes - 1)
@@ -104,6 +113,7 @@ impl<T: Clone + Debug + Send + Sync> Messaging<T> {
 
         Messaging {
             num_nodes: num_nodes,
+            node_index: node_index,
 
             // internally used handles
             to_comms_txs: to_comms_txs,
@@ -140,13 +150,18 @@ impl<T: Clone + Debug + Send + Sync> Messaging<T> {
     }
 
     /// Spawns the message delivery thread in a given thread scope.
-    pub fn spawn<'a>(&self, scope: &Scope<'a>)
+    /// `shutdown_rx` stops the routing loop, letting the thread join, once a
+    /// `()` has been sent on it; routing happens for as long as the rest of
+    /// the node has a use for it, so this is driven from `Node::run` once it
+    /// has everything it needs.
+    pub fn spawn<'a>(&self, scope: &Scope<'a>, shutdown_rx: Receiver<()>)
     where T: 'a
     {
         let to_comms_txs = self.to_comms_txs.to_owned();
         let from_comms_rx = self.from_comms_rx.to_owned();
         let to_algo_txs = self.to_algo_txs.to_owned();
         let from_algo_rx = self.from_algo_rx.to_owned();
+        let node_index = self.node_index;
 
         scope.spawn(move || {
             // This loop forwards messages according to their metadata.
@@ -167,25 +182,64 @@ impl<T: Clone + Debug + Send + Sync> Messaging<T> {
                         } => {
                             // Remote node indices start from 1.
                             assert!(i > 0);
-                            // Convert node index to vector index.
-                            let i = i - 1;
 
-                            if i < to_comms_txs.len() {
-                                to_comms_txs[i].send(message.clone())
-                                    .unwrap();
-                            }
-                            else {
-                                error!("Target {} does not exist", i);
+                            if i == node_index {
+                                error!("Refusing to route a message to our own node index {}", i);
+                            } else {
+                                let slot = comms_slot(node_index, i);
+                                if slot < to_comms_txs.len() {
+                                    to_comms_txs[slot].send(message.clone())
+                                        .unwrap();
+                                }
+                                else {
+                                    error!("Target {} does not exist", i);
+                                }
                             }
                         }
                     }
                 },
                 recv(from_comms_rx, message) => {
-                    for tx in to_algo_txs.iter() {
-                        tx.send(message.clone()).unwrap();
+                    match message.message.instance() {
+                        Some(instance) => {
+                            // Remote node indices, and by extension instance
+                            // indices, start from 1.
+                            assert!(instance > 0);
+                            let i = instance as usize - 1;
+                            if i < to_algo_txs.len() {
+                                to_algo_txs[i].send(message).unwrap();
+                            }
+                            else {
+                                error!("Instance {} does not exist", i);
+                            }
+                        },
+                        None => {
+                            // Not yet tied to an instance; deliver to every
+                            // instance running locally.
+                            for tx in to_algo_txs.iter() {
+                                tx.send(message.clone()).unwrap();
+                            }
+                        }
                     }
+                },
+                recv(shutdown_rx, _) => {
+                    break;
                 }
             }} // end of select_loop!
         });
     }
 }
+
+/// Maps a peer's global 1-based node index onto a slot in the comms-channel
+/// arrays (`to_comms_txs`/`to_comms_rxs`), which are one shorter than
+/// `num_nodes` because there is no comms channel to oneself: peer indices
+/// past `own_index` are shifted down one slot further than peer indices
+/// before it, to close the gap `own_index`'s absence leaves in the
+/// numbering. Not meaningful for `peer_index == own_index`; callers must
+/// guard against routing to oneself separately.
+pub fn comms_slot(own_index: usize, peer_index: usize) -> usize {
+    if peer_index < own_index {
+        peer_index - 1
+    } else {
+        peer_index - 2
+    }
+}
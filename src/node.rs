@@ -0,0 +1,183 @@
+//! Wires connections, message routing and the consensus algorithms together
+//! into a single running node.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use crossbeam;
+use crossbeam_channel::unbounded;
+use ring::digest::SHA256;
+use threshold_crypto::{PublicKeySet, SecretKeyShare};
+use connection;
+use commst;
+use messaging::{Messaging, comms_slot};
+use proto::{Message, DecryptionMessage};
+use acs::Acs;
+
+/// A node taking part in consensus, bound to a local address and connected
+/// to its peers' addresses.
+pub struct Node<T>
+    where T: Clone + Debug + Send + Sync + From<Vec<u8>> + Into<Vec<u8>>
+{
+    bind_address: SocketAddr,
+    remote_addresses: Vec<SocketAddr>,
+    /// This node's proposed value, if it is proposing one for this round.
+    value: Option<T>,
+    /// The group's threshold encryption public key, used to hide proposed
+    /// values from the network until they are reliably broadcast, and to
+    /// derive every instance's common coin.
+    public_key_set: PublicKeySet,
+    /// This node's share of the corresponding threshold secret key.
+    secret_key_share: SecretKeyShare,
+}
+
+impl<T> Node<T>
+    where T: Clone + Debug + Send + Sync + From<Vec<u8>> + Into<Vec<u8>>
+{
+    /// Creates a new node. `public_key_set` and `secret_key_share` are the
+    /// group's threshold key and this node's share of it, generated once by
+    /// a trusted dealer and distributed out of band.
+    pub fn new(bind_address: SocketAddr,
+               remote_addresses: Vec<SocketAddr>,
+               value: Option<T>,
+               public_key_set: PublicKeySet,
+               secret_key_share: SecretKeyShare)
+               -> Self
+    {
+        Node {
+            bind_address,
+            remote_addresses,
+            value,
+            public_key_set,
+            secret_key_share,
+        }
+    }
+
+    /// The 1-based index of `addr` among the full, sorted set of node
+    /// addresses. Every node computes the same index for the same peer,
+    /// since all of them see the same address set.
+    fn global_index(&self, addr: &SocketAddr) -> usize {
+        let mut all_addresses = self.remote_addresses.clone();
+        all_addresses.push(self.bind_address);
+        all_addresses.sort();
+        all_addresses.binary_search(addr).unwrap() + 1
+    }
+
+    /// This node's own global index; see `global_index`.
+    fn node_index(&self) -> usize {
+        self.global_index(&self.bind_address)
+    }
+
+    /// Connects to every other node, runs the asynchronous common subset
+    /// protocol over every node's (threshold-encrypted) proposed value, and
+    /// recovers the plaintext of every ciphertext in the resulting subset.
+    pub fn run(self) -> io::Result<HashMap<usize, T>> {
+        let num_nodes = self.remote_addresses.len() + 1;
+        let f = (num_nodes - 1) / 3;
+        let node_index = self.node_index();
+        let public_key_set = self.public_key_set.clone();
+        let secret_key_share = self.secret_key_share.clone();
+
+        let cipher_value = self.value.map(|v| {
+            let bytes: Vec<u8> = v.into();
+            let ciphertext = public_key_set.public_key().encrypt(&bytes);
+            T::from(ciphertext.to_bytes())
+        });
+
+        let messaging: Messaging<T> = Messaging::new(num_nodes, node_index);
+        let (subset_tx, subset_rx) = unbounded();
+        let (decryption_tx, decryption_rx) = unbounded();
+        let (shutdown_tx, shutdown_rx) = unbounded();
+
+        crossbeam::scope(|scope| {
+            messaging.spawn(scope, shutdown_rx.clone());
+
+            let streams = connection::make_connections(&self.bind_address,
+                                                         &self.remote_addresses);
+            // `make_connections` returns one stream per `remote_addresses`
+            // entry, in that order; each peer's comms slot and the
+            // `node_index` its inbound messages should be tagged with are
+            // both derived from its actual global index, not its position in
+            // this (unsorted) array.
+            for (addr, stream) in self.remote_addresses.iter().zip(streams) {
+                let peer_index = self.global_index(addr);
+                let slot = comms_slot(node_index, peer_index);
+                commst::spawn(scope, peer_index, stream, &SHA256, num_nodes,
+                               messaging.to_comms_rxs()[slot].clone(),
+                               messaging.from_comms_tx().clone());
+            }
+
+            Acs::spawn(scope, num_nodes, node_index, &SHA256, cipher_value,
+                       &messaging, public_key_set.clone(), secret_key_share.clone(),
+                       decryption_tx, subset_tx, shutdown_rx.clone());
+
+            let ciphertexts: HashMap<usize, Vec<u8>> = subset_rx.recv()
+                .expect("ACS produced no output")
+                .into_iter()
+                .map(|(instance, value)| (instance, value.into()))
+                .collect();
+
+            let algo_tx = messaging.from_algo_tx();
+            for (&instance, ciphertext) in ciphertexts.iter() {
+                if let Ok(share) = secret_key_share.decrypt_share(ciphertext) {
+                    let message = DecryptionMessage {
+                        instance: instance as u32,
+                        share: share.to_bytes(),
+                    };
+                    let t = ::messaging::TargetedMessage::new(
+                        ::messaging::Target::All, Message::Decryption(message));
+                    if let Some(t) = t {
+                        algo_tx.send(t).unwrap();
+                    }
+                }
+            }
+
+            // Seed our own shares, then collect others' until every
+            // ciphertext in the subset has f + 1.
+            let mut shares: HashMap<usize, HashMap<usize, ::threshold_crypto::DecryptionShare>> =
+                HashMap::new();
+            for &instance in ciphertexts.keys() {
+                if let Ok(share) = secret_key_share.decrypt_share(&ciphertexts[&instance]) {
+                    shares.entry(instance).or_insert_with(HashMap::new)
+                        .insert(node_index, share);
+                }
+            }
+            while ciphertexts.keys().any(|i| {
+                shares.get(i).map(|s| s.len()).unwrap_or(0) < f + 1
+            }) {
+                let sourced = match decryption_rx.recv() {
+                    Some(s) => s,
+                    None => break,
+                };
+                if let Message::Decryption(DecryptionMessage { instance, share }) = sourced.message {
+                    let instance = instance as usize;
+                    if !ciphertexts.contains_key(&instance) {
+                        continue;
+                    }
+                    if let Ok(share) = ::threshold_crypto::DecryptionShare::from_bytes(&share) {
+                        shares.entry(instance).or_insert_with(HashMap::new)
+                            .insert(sourced.source, share);
+                    }
+                }
+            }
+
+            let mut result = HashMap::new();
+            for (&instance, ciphertext) in ciphertexts.iter() {
+                if let Some(instance_shares) = shares.get(&instance) {
+                    if let Ok(plaintext) = public_key_set.decrypt(instance_shares.iter(), ciphertext) {
+                        result.insert(instance, T::from(plaintext));
+                    }
+                }
+            }
+
+            // Nothing further will be routed or coin-requested; release the
+            // message router and every common coin instance so their
+            // threads join and this scope can actually return.
+            for _ in 0 .. num_nodes + 1 {
+                shutdown_tx.send(()).unwrap();
+            }
+
+            Ok(result)
+        })
+    }
+}
@@ -1,45 +1,148 @@
 //! Construction of messages from protobuf buffers.
 pub mod message;
 
-use ring::digest::Algorithm;
+use std::ptr;
+use ring::digest::{Algorithm, SHA256};
 use merkle::proof::{Proof, Lemma, Positioned};
 //use protobuf::Message;
 use self::message::*;
 use protobuf::error::ProtobufResult;
 use protobuf::core::parse_from_bytes;
 
+/// The Reed-Solomon parameters a value was sharded with. Advertised on every
+/// `ProofProto` so a receiver configured with a different data/parity split
+/// rejects the proof instead of reconstructing garbage from mismatched
+/// shards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErasureCoding {
+    pub data_shard_count: u32,
+    pub parity_shard_count: u32,
+}
+
+impl ErasureCoding {
+    /// The parameters this node expects a value to be sharded with, given
+    /// `num_nodes` consensus nodes and the standard `f = (num_nodes - 1) / 3`
+    /// fault tolerance: `f + 1` data shards, `num_nodes - f - 1` parity
+    /// shards.
+    pub fn expected(num_nodes: usize) -> Self {
+        let f = (num_nodes - 1) / 3;
+        let data_shard_count = f + 1;
+        ErasureCoding {
+            data_shard_count: data_shard_count as u32,
+            parity_shard_count: (num_nodes - data_shard_count) as u32,
+        }
+    }
+}
+
+/// Maps a digest algorithm to its wire identifier. Only `SHA256` is
+/// supported; `None` signals an algorithm this build cannot advertise.
+fn digest_algorithm_id(algorithm: &'static Algorithm) -> Option<DigestAlgorithmProto> {
+    if ptr::eq(algorithm, &SHA256) {
+        Some(DigestAlgorithmProto::SHA256)
+    }
+    else {
+        None
+    }
+}
+
 /// Kinds of message sent by nodes participating in consensus.
+///
+/// `Broadcast` and `Agreement` messages are tagged with the index of the
+/// proposer whose reliable-broadcast/agreement instance they belong to, so
+/// `Messaging` can route them straight to that instance instead of fanning
+/// them out to every instance running locally.
 pub enum Message<T> {
-    Broadcast(BroadcastMessage<T>),
-    Agreement(AgreementMessage)
+    Broadcast(u32, BroadcastMessage<T>),
+    Agreement(u32, AgreementMessage),
+    Coin(CoinMessage),
+    Decryption(DecryptionMessage)
+}
+
+impl<T> Message<T> {
+    /// The instance this message is addressed to, if it belongs to one.
+    /// `Coin` messages are not yet tied to an instance and are still
+    /// delivered to every locally running instance.
+    pub fn instance(&self) -> Option<u32> {
+        match *self {
+            Message::Broadcast(instance, _) => Some(instance),
+            Message::Agreement(instance, _) => Some(instance),
+            Message::Decryption(DecryptionMessage { instance, .. }) => Some(instance),
+            Message::Coin(_) => None,
+        }
+    }
 }
 
 /// The three kinds of message sent during the reliable broadcast stage of the
-/// consensus algorithm.
+/// consensus algorithm. `Value`'s payload may be a plaintext batch or, when
+/// threshold encryption is in use, a serialised ciphertext awaiting
+/// decryption shares once it is delivered. `Value` and `Echo` carry the
+/// erasure-coding parameters the proof's shard was cut with, alongside the
+/// proof itself.
 pub enum BroadcastMessage<T> {
-    Value(Proof<T>),
-    Echo(Proof<T>),
+    Value(ErasureCoding, Proof<T>),
+    Echo(ErasureCoding, Proof<T>),
     Ready(Vec<u8>)
 }
 
 /// Messages sent during the binary Byzantine agreement stage.
+///
+/// `r` is the agreement round the message belongs to; `v` is the bit it
+/// carries.
 pub enum AgreementMessage {
-    // TODO
+    BVal(u32, bool),
+    Aux(u32, bool)
+}
+
+/// A node's share of the threshold signature over `(instance, id)`,
+/// contributed towards the common coin for round `id` of agreement instance
+/// `instance`. `instance` travels in the payload, not the envelope: `Coin`
+/// messages are still fanned out to every locally running instance (see
+/// `Message::instance`), so each instance needs it to recognise shares meant
+/// for a different instance and to sign/verify over a payload unique to
+/// itself.
+pub struct CoinMessage {
+    pub instance: u32,
+    pub id: u32,
+    pub share: Vec<u8>
+}
+
+/// A node's share of the threshold decryption key, contributed towards
+/// recovering the plaintext of the ciphertext delivered by broadcast
+/// instance `instance`.
+pub struct DecryptionMessage {
+    pub instance: u32,
+    pub share: Vec<u8>
 }
 
 impl<T> Message<T> {
-    /// Translation from protobuf to the regular type.
+    /// Translation from protobuf to the regular type. `algorithm` and
+    /// `num_nodes` are this node's own configuration, against which any
+    /// broadcast proof's advertised digest algorithm and erasure-coding
+    /// parameters are checked.
     pub fn from_proto(algorithm: &'static Algorithm,
+                      num_nodes: usize,
                       mut proto: message::MessageProto) -> Option<Self>
     where T: From<Vec<u8>>
     {
         if proto.has_broadcast() {
-            BroadcastMessage::from_proto(proto.take_broadcast(), algorithm)
-                .map(|b| Message::Broadcast(b))
+            let b = proto.take_broadcast();
+            let instance = b.get_instance();
+            BroadcastMessage::from_proto(b, algorithm, num_nodes)
+                .map(|b| Message::Broadcast(instance, b))
         }
         else if proto.has_agreement() {
-            AgreementMessage::from_proto(proto.take_agreement())
-                .map(|a| Message::Agreement(a))
+            let a = proto.take_agreement();
+            let instance = a.get_instance();
+            AgreementMessage::from_proto(a)
+                .map(|a| Message::Agreement(instance, a))
+        }
+        else if proto.has_coin() {
+            CoinMessage::from_proto(proto.take_coin())
+                .map(|c| Message::Coin(c))
+        }
+        else if proto.has_decryption() {
+            DecryptionMessage::from_proto(proto.take_decryption())
+                .map(|d| Message::Decryption(d))
         }
         else {
             None
@@ -51,11 +154,21 @@ impl<T> Message<T> {
     {
         let mut m = MessageProto::new();
         match self {
-            Message::Broadcast(b) => {
-                m.set_broadcast(b.into_proto());
+            Message::Broadcast(instance, b) => {
+                let mut proto = b.into_proto();
+                proto.set_instance(instance);
+                m.set_broadcast(proto);
+            },
+            Message::Agreement(instance, a) => {
+                let mut proto = a.into_proto();
+                proto.set_instance(instance);
+                m.set_agreement(proto);
+            },
+            Message::Coin(c) => {
+                m.set_coin(c.into_proto());
             },
-            Message::Agreement(a) => {
-                m.set_agreement(a.into_proto());
+            Message::Decryption(d) => {
+                m.set_decryption(d.into_proto());
             }
         }
         m
@@ -68,36 +181,43 @@ impl<T> BroadcastMessage<T> {
     {
         let mut b = BroadcastProto::new();
         match self {
-            BroadcastMessage::Value(p) => {
+            BroadcastMessage::Value(ec, p) => {
                 let mut v = ValueProto::new();
-                v.set_proof(ProofProto::into_proto(p));
+                v.set_proof(ProofProto::into_proto(p, ec));
                 b.set_value(v);
             },
-            BroadcastMessage::Echo(p) => {
+            BroadcastMessage::Echo(ec, p) => {
                 let mut e = EchoProto::new();
-                e.set_proof(ProofProto::into_proto(p));
+                e.set_proof(ProofProto::into_proto(p, ec));
                 b.set_echo(e);
             },
             BroadcastMessage::Ready(h) => {
                 let mut r = ReadyProto::new();
                 r.set_root_hash(h);
+                b.set_ready(r);
             }
         }
         b
     }
 
+    /// `algorithm` and `num_nodes` are this node's own configuration; a
+    /// `Value` or `Echo` whose proof advertises a different digest algorithm
+    /// or erasure-coding split is rejected rather than reconstructed from
+    /// mismatched shards.
     pub fn from_proto(mut mp: BroadcastProto,
-                      algorithm: &'static Algorithm)
+                      algorithm: &'static Algorithm,
+                      num_nodes: usize)
                       -> Option<Self>
     where T: From<Vec<u8>>
     {
+        let expected = ErasureCoding::expected(num_nodes);
         if mp.has_value() {
-            mp.take_value().take_proof().from_proto(algorithm)
-                .map(|p| BroadcastMessage::Value(p))
+            mp.take_value().take_proof().from_proto(algorithm, expected)
+                .map(|(ec, p)| BroadcastMessage::Value(ec, p))
         }
         else if mp.has_echo() {
-            mp.take_echo().take_proof().from_proto(algorithm)
-                .map(|p| BroadcastMessage::Echo(p))
+            mp.take_echo().take_proof().from_proto(algorithm, expected)
+                .map(|(ec, p)| BroadcastMessage::Echo(ec, p))
         }
         else if mp.has_ready() {
             let h = mp.take_ready().take_root_hash();
@@ -112,12 +232,81 @@ impl<T> BroadcastMessage<T> {
 impl AgreementMessage {
     pub fn into_proto(self) -> AgreementProto
     {
-        unimplemented!();
+        let mut a = AgreementProto::new();
+        match self {
+            AgreementMessage::BVal(round, value) => {
+                let mut b = BValProto::new();
+                b.set_round(round);
+                b.set_value(value);
+                a.set_bval(b);
+            },
+            AgreementMessage::Aux(round, value) => {
+                let mut x = AuxProto::new();
+                x.set_round(round);
+                x.set_value(value);
+                a.set_aux(x);
+            }
+        }
+        a
     }
 
     pub fn from_proto(mut mp: AgreementProto) -> Option<Self>
     {
-        unimplemented!();
+        if mp.has_bval() {
+            let b = mp.take_bval();
+            Some(AgreementMessage::BVal(b.get_round(), b.get_value()))
+        }
+        else if mp.has_aux() {
+            let x = mp.take_aux();
+            Some(AgreementMessage::Aux(x.get_round(), x.get_value()))
+        }
+        else {
+            None
+        }
+    }
+}
+
+impl CoinMessage {
+    pub fn into_proto(self) -> CoinProto
+    {
+        let mut c = CoinProto::new();
+        c.set_instance(self.instance);
+        c.set_id(self.id);
+        c.set_share(self.share);
+        c
+    }
+
+    pub fn from_proto(mut mp: CoinProto) -> Option<Self>
+    {
+        if !mp.has_share() {
+            return None;
+        }
+        Some(CoinMessage {
+            instance: mp.get_instance(),
+            id: mp.get_id(),
+            share: mp.take_share(),
+        })
+    }
+}
+
+impl DecryptionMessage {
+    pub fn into_proto(self) -> DecryptionProto
+    {
+        let mut d = DecryptionProto::new();
+        d.set_instance(self.instance);
+        d.set_share(self.share);
+        d
+    }
+
+    pub fn from_proto(mut mp: DecryptionProto) -> Option<Self>
+    {
+        if !mp.has_share() {
+            return None;
+        }
+        Some(DecryptionMessage {
+            instance: mp.get_instance(),
+            share: mp.take_share(),
+        })
     }
 }
 
@@ -125,7 +314,7 @@ impl AgreementMessage {
 /// around the restriction of not being allowed to extend the implementation of
 /// `Proof` outside its crate.
 impl ProofProto {
-    pub fn into_proto<T>(proof: Proof<T>) -> Self
+    pub fn into_proto<T>(proof: Proof<T>, erasure_coding: ErasureCoding) -> Self
     where T: Into<Vec<u8>>
     {
 
@@ -133,11 +322,16 @@ impl ProofProto {
 
         match proof {
             Proof {
+                algorithm,
                 root_hash,
                 lemma,
                 value,
                 ..
             } => {
+                proto.set_algorithm(digest_algorithm_id(algorithm)
+                    .expect("Proof uses an unsupported digest algorithm"));
+                proto.set_data_shard_count(erasure_coding.data_shard_count);
+                proto.set_parity_shard_count(erasure_coding.parity_shard_count);
                 proto.set_root_hash(root_hash);
                 proto.set_lemma(LemmaProto::into_proto(lemma));
                 proto.set_value(value.into());
@@ -147,22 +341,40 @@ impl ProofProto {
         proto
     }
 
+    /// `algorithm` is this node's own digest algorithm; `expected` is the
+    /// erasure-coding split this node expects a value to be sharded with.
+    /// Returns `None` if the proof's advertised algorithm or shard counts
+    /// don't match, rather than reconstructing from parameters this node
+    /// didn't agree to.
     pub fn from_proto<T>(mut self,
-                         algorithm: &'static Algorithm)
-                         -> Option<Proof<T>>
+                         algorithm: &'static Algorithm,
+                         expected: ErasureCoding)
+                         -> Option<(ErasureCoding, Proof<T>)>
     where T: From<Vec<u8>>
     {
         if !self.has_lemma() {
             return None;
         }
+        if digest_algorithm_id(algorithm) != Some(self.get_algorithm()) {
+            return None;
+        }
+
+        let erasure_coding = ErasureCoding {
+            data_shard_count: self.get_data_shard_count(),
+            parity_shard_count: self.get_parity_shard_count(),
+        };
+        if erasure_coding != expected {
+            return None;
+        }
 
         self.take_lemma().from_proto().map(|lemma| {
-            Proof::new(
+            let proof = Proof::new(
                 algorithm,
                 self.take_root_hash(),
                 lemma,
                 self.take_value().into(),
-            )
+            );
+            (erasure_coding, proof)
         })
     }
 }
@@ -0,0 +1,67 @@
+//! Framing of protobuf messages on a byte stream.
+use std::io::{self, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use protobuf::core::{Message as ProtobufMessage, parse_from_bytes};
+use protobuf::ProtobufError;
+use ring::digest::Algorithm;
+use proto::message::MessageProto;
+use proto::Message;
+
+/// The largest frame `recv_proto` will allocate a buffer for. Generous enough
+/// for any legitimate message this protocol sends (erasure-coded shards and
+/// Merkle proofs included), while still bounding how much memory a bogus or
+/// malicious length prefix can make us allocate before we've even looked at
+/// the bytes it's supposedly introducing.
+const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Reads and writes length-delimited `MessageProto` frames on a stream: a
+/// four byte big-endian length prefix followed by that many bytes of
+/// serialised protobuf. `algorithm` and `num_nodes` are this node's own
+/// configuration, used to validate the erasure-coding parameters advertised
+/// by incoming broadcast proofs.
+pub struct ProtoIo<S: Read + Write> {
+    stream: S,
+    algorithm: &'static Algorithm,
+    num_nodes: usize,
+}
+
+impl<S: Read + Write> ProtoIo<S> {
+    pub fn new(stream: S, algorithm: &'static Algorithm, num_nodes: usize) -> Self {
+        ProtoIo { stream, algorithm, num_nodes }
+    }
+
+    pub fn recv_proto(&mut self) -> io::Result<MessageProto> {
+        let len = self.stream.read_u32::<BigEndian>()?;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame length {} exceeds the {} byte maximum", len, MAX_FRAME_LEN)));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.stream.read_exact(&mut buf)?;
+        parse_from_bytes(&buf).map_err(protobuf_to_io_error)
+    }
+
+    pub fn send_proto(&mut self, proto: &MessageProto) -> io::Result<()> {
+        let buf = proto.write_to_bytes().map_err(protobuf_to_io_error)?;
+        self.stream.write_u32::<BigEndian>(buf.len() as u32)?;
+        self.stream.write_all(&buf)
+    }
+
+    pub fn recv_message<T>(&mut self) -> io::Result<Option<Message<T>>>
+    where T: From<Vec<u8>>
+    {
+        let proto = self.recv_proto()?;
+        Ok(Message::from_proto(self.algorithm, self.num_nodes, proto))
+    }
+
+    pub fn send_message<T>(&mut self, message: Message<T>) -> io::Result<()>
+    where T: Into<Vec<u8>>
+    {
+        self.send_proto(&message.into_proto())
+    }
+}
+
+fn protobuf_to_io_error(err: ProtobufError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}